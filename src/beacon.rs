@@ -0,0 +1,363 @@
+//! A rendezvous fallback for peers that aren't on the same mDNS link.
+//!
+//! Unlike `DiscoveryService`, which only sees peers on the local multicast
+//! domain, `BeaconService` periodically publishes the set of peers it
+//! currently knows about as a small obfuscated token, and reads the same
+//! kind of token back in to learn about peers published elsewhere. The
+//! token is exchanged out-of-band: either dropped in a shared file or piped
+//! through a user-supplied shell command.
+
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    process::Command,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use smol::channel::Sender;
+
+use crate::{dns, Config, Message};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub struct BeaconService {
+    config: Config,
+    peers: Arc<Mutex<HashSet<SocketAddr>>>,
+}
+
+impl BeaconService {
+    /// Build a `BeaconService` from the CLI config, or `None` if the user
+    /// didn't opt in (a passphrase and a publish target are both required).
+    pub fn new(config: &Config, peers: Arc<Mutex<HashSet<SocketAddr>>>) -> Option<Self> {
+        config.beacon_passphrase.as_ref()?;
+        if config.beacon_command.is_none() && config.beacon_file.is_none() {
+            return None;
+        }
+        Some(Self {
+            config: config.clone(),
+            peers,
+        })
+    }
+
+    pub async fn run(self, tx: Sender<Message>) -> Result<()> {
+        info!("STARTING BEACON");
+        smol::future::try_zip(self.publish_loop(), self.read_loop(tx)).await?;
+        Ok(())
+    }
+
+    async fn publish_loop(&self) -> Result<()> {
+        let interval = self.interval();
+        loop {
+            let snapshot: Vec<SocketAddr> = {
+                let peers = self.peers.lock().unwrap();
+                peers.iter().copied().collect()
+            };
+            let token = encode_token(&snapshot, self.passphrase(), time_bucket(interval));
+            if let Err(e) = self.publish(&token) {
+                warn!("failed to publish beacon token: {e}");
+            }
+            futures_timer::Delay::new(interval).await;
+        }
+    }
+
+    async fn read_loop(&self, tx: Sender<Message>) -> Result<()> {
+        let interval = self.interval();
+        loop {
+            match self.read() {
+                Ok(Some(raw)) => match decode_token_near(&raw, self.passphrase(), interval) {
+                    Ok(addrs) => self.admit_peers(addrs, &tx).await?,
+                    Err(e) => warn!("failed to decode beacon token: {e}"),
+                },
+                Ok(None) => {}
+                Err(e) => warn!("failed to read beacon token: {e}"),
+            }
+            futures_timer::Delay::new(interval).await;
+        }
+    }
+
+    async fn admit_peers(&self, addrs: Vec<SocketAddr>, tx: &Sender<Message>) -> Result<()> {
+        for addr in addrs {
+            let is_new = self.peers.lock().unwrap().insert(addr);
+            if is_new {
+                info!("BEACON discovered peer {addr}");
+                let dns_msg = dns::Message {
+                    flags: dns::Flags {
+                        response: true,
+                        recursion_desired: false,
+                    },
+                    answers: vec![dns::Record {
+                        name: "_beacon._udp.local.".to_string(),
+                        rtype: dns::RecordType::Ptr,
+                        rclass: dns::CLASS_IN,
+                        ttl: 120,
+                        rdata: addr.to_string().into_bytes(),
+                    }],
+                    ..Default::default()
+                };
+                let mut msg = Message::new(addr);
+                msg.set_buf(dns_msg.encode()?)?;
+                tx.send(msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn publish(&self, token: &str) -> Result<()> {
+        if let Some(path) = &self.config.beacon_file {
+            std::fs::write(path, token)?;
+        }
+        if let Some(command) = &self.config.beacon_command {
+            Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("begin", "1")
+                .env("data", token)
+                .env("end", "1")
+                .status()?;
+        }
+        Ok(())
+    }
+
+    fn read(&self) -> Result<Option<String>> {
+        if let Some(path) = &self.config.beacon_file {
+            return Ok(Some(std::fs::read_to_string(path)?.trim().to_string()));
+        }
+        if let Some(command) = &self.config.beacon_command {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("begin", "1")
+                .env("end", "1")
+                .output()?;
+            let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            return Ok(if token.is_empty() { None } else { Some(token) });
+        }
+        Ok(None)
+    }
+
+    fn passphrase(&self) -> &str {
+        self.config
+            .beacon_passphrase
+            .as_deref()
+            .expect("BeaconService::new guarantees a passphrase is set")
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.config.beacon_interval_secs.max(1))
+    }
+}
+
+fn time_bucket(interval: Duration) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    now / interval.as_secs().max(1)
+}
+
+/// Serialize `addrs` and XOR them with a passphrase- and time-bucket-derived
+/// keystream, then base64-encode the result. This is obfuscation, not
+/// encryption: it keeps the token from being an obviously readable address
+/// list, not a secret channel.
+fn encode_token(addrs: &[SocketAddr], passphrase: &str, bucket: u64) -> String {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    for addr in addrs {
+        match addr {
+            SocketAddr::V4(v4) => {
+                payload.push(4);
+                payload.extend_from_slice(&v4.ip().octets());
+                payload.extend_from_slice(&v4.port().to_be_bytes());
+            }
+            SocketAddr::V6(v6) => {
+                payload.push(6);
+                payload.extend_from_slice(&v6.ip().octets());
+                payload.extend_from_slice(&v6.port().to_be_bytes());
+            }
+        }
+    }
+    xor_with_keystream(&mut payload, passphrase, bucket);
+    base64_encode(&payload)
+}
+
+/// Decode a token, trying the current time bucket and its immediate
+/// neighbors to tolerate clock skew between the publisher and reader.
+fn decode_token_near(token: &str, passphrase: &str, interval: Duration) -> Result<Vec<SocketAddr>> {
+    let bucket = time_bucket(interval);
+    let mut last_err = anyhow!("empty beacon token");
+    for candidate in [bucket, bucket.saturating_sub(1), bucket + 1] {
+        match decode_token(token, passphrase, candidate) {
+            Ok(addrs) => return Ok(addrs),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+fn decode_token(token: &str, passphrase: &str, bucket: u64) -> Result<Vec<SocketAddr>> {
+    let mut payload = base64_decode(token)?;
+    xor_with_keystream(&mut payload, passphrase, bucket);
+
+    if payload.len() < 2 {
+        return Err(anyhow!("beacon token too short"));
+    }
+    let count = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+    let mut pos = 2;
+    let mut addrs = Vec::with_capacity(count);
+    for _ in 0..count {
+        let tag = *payload
+            .get(pos)
+            .ok_or_else(|| anyhow!("truncated beacon token"))?;
+        pos += 1;
+        let addr = match tag {
+            4 => {
+                let bytes = payload
+                    .get(pos..pos + 4)
+                    .ok_or_else(|| anyhow!("truncated beacon token"))?;
+                let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+                pos += 4;
+                let port = read_port(&payload, &mut pos)?;
+                SocketAddr::from((ip, port))
+            }
+            6 => {
+                let bytes = payload
+                    .get(pos..pos + 16)
+                    .ok_or_else(|| anyhow!("truncated beacon token"))?;
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(bytes);
+                pos += 16;
+                let port = read_port(&payload, &mut pos)?;
+                SocketAddr::from((Ipv6Addr::from(octets), port))
+            }
+            other => return Err(anyhow!("unknown address tag {other} in beacon token")),
+        };
+        addrs.push(addr);
+    }
+    Ok(addrs)
+}
+
+fn read_port(payload: &[u8], pos: &mut usize) -> Result<u16> {
+    let bytes = payload
+        .get(*pos..*pos + 2)
+        .ok_or_else(|| anyhow!("truncated beacon token"))?;
+    *pos += 2;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn keystream(passphrase: &str, bucket: u64, len: usize) -> Vec<u8> {
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+    };
+
+    let mut out = Vec::with_capacity(len);
+    let mut counter = 0u64;
+    while out.len() < len {
+        let mut hasher = DefaultHasher::new();
+        passphrase.hash(&mut hasher);
+        bucket.hash(&mut hasher);
+        counter.hash(&mut hasher);
+        out.extend_from_slice(&hasher.finish().to_be_bytes());
+        counter += 1;
+    }
+    out.truncate(len);
+    out
+}
+
+fn xor_with_keystream(data: &mut [u8], passphrase: &str, bucket: u64) {
+    let len = data.len();
+    for (byte, key) in data.iter_mut().zip(keystream(passphrase, bucket, len)) {
+        *byte ^= key;
+    }
+}
+
+/// Encode `bytes` as standard base64 with padding. Unlike treating the
+/// payload as one big base62 integer, this works in fixed 3-byte groups, so
+/// leading zero bytes (which the XOR keystream produces about 1 in 256
+/// publish cycles) round-trip correctly instead of silently disappearing.
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b0 = chunk[0];
+        let b1 = chunk.get(1).copied();
+        let b2 = chunk.get(2).copied();
+
+        out.push(BASE64_ALPHABET[(b0 >> 2) as usize] as char);
+        out.push(BASE64_ALPHABET[(((b0 & 0x03) << 4) | (b1.unwrap_or(0) >> 4)) as usize] as char);
+        out.push(match b1 {
+            Some(b1) => BASE64_ALPHABET[(((b1 & 0x0F) << 2) | (b2.unwrap_or(0) >> 6)) as usize] as char,
+            None => '=',
+        });
+        out.push(match b2 {
+            Some(b2) => BASE64_ALPHABET[(b2 & 0x3F) as usize] as char,
+            None => '=',
+        });
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * 3 / 4 + 3);
+    let mut bits = 0u32;
+    let mut nbits = 0u32;
+    for c in s.bytes() {
+        let value = BASE64_ALPHABET
+            .iter()
+            .position(|&b| b == c)
+            .ok_or_else(|| anyhow!("invalid base64 character {:?}", c as char))? as u32;
+        bits = (bits << 6) | value;
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            out.push((bits >> nbits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_then_decode_token_round_trips() {
+        let addrs = vec![
+            SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 4242)),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 1)),
+        ];
+        let bucket = 12345;
+        let token = encode_token(&addrs, "correct horse battery staple", bucket);
+        let decoded =
+            decode_token(&token, "correct horse battery staple", bucket).expect("decode should succeed");
+        assert_eq!(decoded, addrs);
+    }
+
+    #[test]
+    fn encode_then_decode_token_round_trips_with_leading_zero_byte() {
+        // Regression test: the payload's first byte after XOR is 0x00 about
+        // 1 in 256 publish cycles. The old base62 codec silently dropped
+        // that leading zero byte on decode; base64 must not.
+        let addrs = vec![SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 1))];
+        // Brute-force a passphrase/bucket pair whose keystream happens to
+        // start with 0x00, so this test actually exercises the edge case
+        // rather than relying on luck.
+        let (passphrase, bucket) = (0..10_000u64)
+            .map(|bucket| (format!("passphrase-{bucket}"), bucket))
+            .find(|(passphrase, bucket)| keystream(passphrase, *bucket, 1)[0] == 0)
+            .expect("some bucket in range produces a leading zero keystream byte");
+
+        let token = encode_token(&addrs, &passphrase, bucket);
+        let decoded = decode_token(&token, &passphrase, bucket).expect("decode should succeed");
+        assert_eq!(decoded, addrs);
+    }
+
+    #[test]
+    fn decode_token_rejects_garbage() {
+        assert!(decode_token("not valid base64!!", "passphrase", 0).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+//! A minimal RFC 1035 DNS / mDNS wire codec.
+//!
+//! This covers just enough of the format to read and write mDNS traffic: the
+//! 12-byte header, questions, and resource records for the record types mDNS
+//! commonly carries (A, AAAA, PTR, SRV, TXT). Name compression pointers are
+//! followed on decode but never produced on encode.
+
+use anyhow::{anyhow, Result};
+
+/// The `IN` (Internet) query/record class.
+pub const CLASS_IN: u16 = 1;
+
+/// DNS resource record types this crate understands. Anything else decodes
+/// to `Other` so unknown records can still round-trip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Ptr,
+    Srv,
+    Txt,
+    Other(u16),
+}
+
+impl RecordType {
+    fn from_u16(v: u16) -> Self {
+        match v {
+            1 => RecordType::A,
+            28 => RecordType::Aaaa,
+            12 => RecordType::Ptr,
+            33 => RecordType::Srv,
+            16 => RecordType::Txt,
+            other => RecordType::Other(other),
+        }
+    }
+
+    fn to_u16(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+            RecordType::Ptr => 12,
+            RecordType::Srv => 33,
+            RecordType::Txt => 16,
+            RecordType::Other(v) => v,
+        }
+    }
+}
+
+/// The header flags word, trimmed to the bits this crate cares about.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// QR bit: set on responses, clear on queries.
+    pub response: bool,
+    pub recursion_desired: bool,
+}
+
+impl Flags {
+    const RESPONSE_BIT: u16 = 0x8000;
+    const RECURSION_DESIRED_BIT: u16 = 0x0100;
+
+    fn from_u16(v: u16) -> Self {
+        Self {
+            response: v & Self::RESPONSE_BIT != 0,
+            recursion_desired: v & Self::RECURSION_DESIRED_BIT != 0,
+        }
+    }
+
+    fn to_u16(self) -> u16 {
+        let mut v = 0;
+        if self.response {
+            v |= Self::RESPONSE_BIT;
+        }
+        if self.recursion_desired {
+            v |= Self::RECURSION_DESIRED_BIT;
+        }
+        v
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Question {
+    pub name: String,
+    pub qtype: RecordType,
+    pub qclass: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub name: String,
+    pub rtype: RecordType,
+    pub rclass: u16,
+    pub ttl: u32,
+    pub rdata: Vec<u8>,
+}
+
+/// A DNS/mDNS message: a header plus the four standard record sections.
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub id: u16,
+    pub flags: Flags,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+impl Message {
+    /// Encode this message to its RFC 1035 wire representation. Fails if any
+    /// name has a label longer than the 63-byte RFC 1035 limit.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_u16().to_be_bytes());
+        buf.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.answers.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.authorities.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.additionals.len() as u16).to_be_bytes());
+
+        for q in &self.questions {
+            encode_name(&mut buf, &q.name)?;
+            buf.extend_from_slice(&q.qtype.to_u16().to_be_bytes());
+            buf.extend_from_slice(&q.qclass.to_be_bytes());
+        }
+        for section in [&self.answers, &self.authorities, &self.additionals] {
+            for rr in section {
+                encode_record(&mut buf, rr)?;
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Parse a datagram payload as an RFC 1035 message.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 12 {
+            return Err(anyhow!("DNS message shorter than the 12-byte header"));
+        }
+        let mut pos = 0;
+        let id = read_u16(data, &mut pos)?;
+        let flags = Flags::from_u16(read_u16(data, &mut pos)?);
+        let qdcount = read_u16(data, &mut pos)? as usize;
+        let ancount = read_u16(data, &mut pos)? as usize;
+        let nscount = read_u16(data, &mut pos)? as usize;
+        let arcount = read_u16(data, &mut pos)? as usize;
+
+        // Counts come from the untrusted 12-byte header, so don't let them
+        // drive allocation size directly: a single short datagram claiming
+        // 0xFFFF records would force a multi-megabyte allocation before the
+        // length check below ever fails. Let the vectors grow as records
+        // actually decode instead.
+        let mut questions = Vec::new();
+        for _ in 0..qdcount {
+            let name = decode_name(data, &mut pos)?;
+            let qtype = RecordType::from_u16(read_u16(data, &mut pos)?);
+            let qclass = read_u16(data, &mut pos)?;
+            questions.push(Question { name, qtype, qclass });
+        }
+
+        let answers = decode_records(data, &mut pos, ancount)?;
+        let authorities = decode_records(data, &mut pos, nscount)?;
+        let additionals = decode_records(data, &mut pos, arcount)?;
+
+        Ok(Message {
+            id,
+            flags,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+}
+
+fn encode_record(buf: &mut Vec<u8>, rr: &Record) -> Result<()> {
+    encode_name(buf, &rr.name)?;
+    buf.extend_from_slice(&rr.rtype.to_u16().to_be_bytes());
+    buf.extend_from_slice(&rr.rclass.to_be_bytes());
+    buf.extend_from_slice(&rr.ttl.to_be_bytes());
+    buf.extend_from_slice(&(rr.rdata.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&rr.rdata);
+    Ok(())
+}
+
+fn decode_records(data: &[u8], pos: &mut usize, count: usize) -> Result<Vec<Record>> {
+    // See the comment in `Message::parse`: `count` is attacker-controlled.
+    let mut out = Vec::new();
+    for _ in 0..count {
+        let name = decode_name(data, pos)?;
+        let rtype = RecordType::from_u16(read_u16(data, pos)?);
+        let rclass = read_u16(data, pos)?;
+        let ttl = read_u32(data, pos)?;
+        let rdlength = read_u16(data, pos)? as usize;
+        let rdata = data
+            .get(*pos..*pos + rdlength)
+            .ok_or_else(|| anyhow!("truncated rdata"))?
+            .to_vec();
+        *pos += rdlength;
+        out.push(Record {
+            name,
+            rtype,
+            rclass,
+            ttl,
+            rdata,
+        });
+    }
+    Ok(out)
+}
+
+/// The RFC 1035 limit on a single label's length: the top two bits of the
+/// length byte are reserved to tag compression pointers, so a label can be
+/// at most 63 bytes.
+const MAX_LABEL_LEN: usize = 63;
+
+/// Write `name` as length-prefixed labels terminated by a zero byte. Never
+/// emits a compression pointer.
+fn encode_name(buf: &mut Vec<u8>, name: &str) -> Result<()> {
+    for label in name.split('.') {
+        if label.is_empty() {
+            continue;
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(anyhow!(
+                "label {label:?} is {} bytes, over the {MAX_LABEL_LEN}-byte limit",
+                label.len()
+            ));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    Ok(())
+}
+
+/// Read a (possibly compressed) name starting at `*pos`, advancing `*pos`
+/// past it. Compression pointers (the top two bits of the length byte set)
+/// redirect to an earlier offset in `data` without moving `*pos` past the
+/// pointer itself.
+fn decode_name(data: &[u8], pos: &mut usize) -> Result<String> {
+    const POINTER_TAG: u8 = 0xC0;
+    const MAX_LABELS: usize = 128; // guards against compression pointer loops
+
+    let mut labels = Vec::new();
+    let mut cursor = *pos;
+    let mut end_of_name = None;
+    let mut terminated = false;
+
+    for _ in 0..MAX_LABELS {
+        let len = *data
+            .get(cursor)
+            .ok_or_else(|| anyhow!("truncated name"))?;
+        if len == 0 {
+            if end_of_name.is_none() {
+                end_of_name = Some(cursor + 1);
+            }
+            terminated = true;
+            break;
+        } else if len & POINTER_TAG == POINTER_TAG {
+            let lo = *data
+                .get(cursor + 1)
+                .ok_or_else(|| anyhow!("truncated name pointer"))?;
+            if end_of_name.is_none() {
+                end_of_name = Some(cursor + 2);
+            }
+            cursor = (((len & !POINTER_TAG) as usize) << 8) | lo as usize;
+        } else {
+            let start = cursor + 1;
+            let label_end = start + len as usize;
+            let label = data
+                .get(start..label_end)
+                .ok_or_else(|| anyhow!("truncated label"))?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            cursor = label_end;
+        }
+    }
+
+    let end_of_name = match end_of_name {
+        Some(end) if terminated => end,
+        _ => return Err(anyhow!("name compression loop or too many labels")),
+    };
+    *pos = end_of_name;
+    Ok(format!("{}.", labels.join(".")))
+}
+
+fn read_u16(data: &[u8], pos: &mut usize) -> Result<u16> {
+    let bytes = data
+        .get(*pos..*pos + 2)
+        .ok_or_else(|| anyhow!("truncated message"))?;
+    *pos += 2;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Result<u32> {
+    let bytes = data
+        .get(*pos..*pos + 4)
+        .ok_or_else(|| anyhow!("truncated message"))?;
+    *pos += 4;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_message() -> Message {
+        Message {
+            id: 0x1234,
+            flags: Flags {
+                response: true,
+                recursion_desired: true,
+            },
+            questions: vec![Question {
+                name: "_example._tcp.local.".to_string(),
+                qtype: RecordType::Ptr,
+                qclass: CLASS_IN,
+            }],
+            answers: vec![
+                Record {
+                    name: "host.local.".to_string(),
+                    rtype: RecordType::A,
+                    rclass: CLASS_IN,
+                    ttl: 120,
+                    rdata: vec![192, 0, 2, 1],
+                },
+                Record {
+                    name: "host.local.".to_string(),
+                    rtype: RecordType::Aaaa,
+                    rclass: CLASS_IN,
+                    ttl: 120,
+                    rdata: vec![0u8; 16],
+                },
+            ],
+            authorities: vec![Record {
+                name: "_example._tcp.local.".to_string(),
+                rtype: RecordType::Srv,
+                rclass: CLASS_IN,
+                ttl: 120,
+                rdata: vec![0, 0, 0, 0, 0x1f, 0x90],
+            }],
+            additionals: vec![Record {
+                name: "host.local.".to_string(),
+                rtype: RecordType::Txt,
+                rclass: CLASS_IN,
+                ttl: 120,
+                rdata: b"\x05hello".to_vec(),
+            }],
+        }
+    }
+
+    #[test]
+    fn encode_then_parse_round_trips() {
+        let msg = sample_message();
+        let encoded = msg.encode().expect("sample message labels are all within limits");
+        let decoded = Message::parse(&encoded).expect("round-trip parse should succeed");
+
+        assert_eq!(decoded.id, msg.id);
+        assert_eq!(decoded.flags, msg.flags);
+        assert_eq!(decoded.questions.len(), msg.questions.len());
+        assert_eq!(decoded.questions[0].name, msg.questions[0].name);
+        assert_eq!(decoded.questions[0].qtype, msg.questions[0].qtype);
+        assert_eq!(decoded.answers.len(), msg.answers.len());
+        for (got, want) in decoded.answers.iter().zip(&msg.answers) {
+            assert_eq!(got.name, want.name);
+            assert_eq!(got.rtype, want.rtype);
+            assert_eq!(got.ttl, want.ttl);
+            assert_eq!(got.rdata, want.rdata);
+        }
+        assert_eq!(decoded.authorities.len(), msg.authorities.len());
+        assert_eq!(decoded.additionals.len(), msg.additionals.len());
+        assert_eq!(decoded.additionals[0].rdata, msg.additionals[0].rdata);
+    }
+
+    #[test]
+    fn parse_rejects_short_message() {
+        assert!(Message::parse(&[0u8; 4]).is_err());
+    }
+
+    #[test]
+    fn parse_does_not_trust_header_counts_for_allocation() {
+        // A 12-byte header claiming the maximum possible record counts, with
+        // no record data to back them up. This should fail cleanly on the
+        // first decode rather than allocating space for 65535 records.
+        let mut data = vec![0u8; 12];
+        data[4..6].copy_from_slice(&0xFFFFu16.to_be_bytes()); // ancount
+        assert!(Message::parse(&data).is_err());
+    }
+
+    #[test]
+    fn encode_rejects_oversized_label() {
+        let mut msg = sample_message();
+        msg.questions[0].name = "a".repeat(MAX_LABEL_LEN + 1);
+        assert!(msg.encode().is_err());
+    }
+}
@@ -1,7 +1,11 @@
 use std::{
+    collections::HashSet,
+    ffi::CString,
     fmt::Write,
-    net::{IpAddr, SocketAddr},
-    time::Duration,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
@@ -12,6 +16,18 @@ use smol::{
     channel::{bounded, Receiver, Sender},
     net::UdpSocket,
 };
+use socket2::{Domain, Protocol, Socket, Type};
+
+mod beacon;
+mod dns;
+mod mux;
+
+/// Standard mDNS multicast groups (RFC 6762 section 3).
+const MDNS_MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// How long a peer can go quiet before `mux::Demux` forgets about it.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
 
 #[derive(Parser, Debug, Clone)]
 #[command(
@@ -38,105 +54,437 @@ struct Config {
     #[arg(short, default_value = "_example._udp")]
     service_name: String,
 
-    /// Port
-    #[arg(short, default_value = "0")]
+    /// Port to bind for mDNS traffic
+    #[arg(short, default_value = "5353")]
     port: u16,
 
     /// Key=Value properties to share with peers.
     #[arg(value_parser=parse_key_val::<String,String>)]
     properties: Vec<(String, String)>,
+
+    /// Shared passphrase used to obfuscate beacon rendezvous tokens. Beacon
+    /// publishing is disabled unless this and one of `--beacon-command` /
+    /// `--beacon-file` are set.
+    #[arg(long)]
+    beacon_passphrase: Option<String>,
+
+    /// Shell command to publish/read the beacon token through. Invoked via
+    /// `sh -c`, with the token passed in the `data` env var (alongside
+    /// `begin`/`end` markers) when publishing, and read from stdout when
+    /// reading it back.
+    #[arg(long)]
+    beacon_command: Option<String>,
+
+    /// File to publish/read the beacon token through. If `--beacon-command`
+    /// is also set, the token is published through both and read back from
+    /// this file.
+    #[arg(long)]
+    beacon_file: Option<PathBuf>,
+
+    /// How often to publish a new beacon token, in seconds.
+    #[arg(long, default_value = "30")]
+    beacon_interval_secs: u64,
+
+    /// Browse for the service once, print every resolved instance, then
+    /// exit instead of running the persistent discovery/messaging loop.
+    #[arg(long)]
+    once: bool,
+
+    /// How long `--once` browses before giving up, in milliseconds.
+    #[arg(long, default_value = "1000")]
+    timeout_ms: u64,
+
+    /// Only bind/join on interfaces with an address inside one of these
+    /// CIDRs (e.g. `192.168.1.0/24`, `fd00::/8`). May be given multiple
+    /// times. If neither this nor `--iface-name` is set, every usable
+    /// interface is used.
+    #[arg(long = "iface-cidr")]
+    iface_cidrs: Vec<String>,
+
+    /// Only bind/join on interfaces with this exact name (e.g. `eth0`). May
+    /// be given multiple times.
+    #[arg(long = "iface-name")]
+    iface_names: Vec<String>,
 }
 
+/// Maximum size of a single UDP datagram payload (RFC 768, IPv4).
+const MAX_MESSAGE_SIZE: usize = 65_507;
+
 struct Message {
     dst: SocketAddr,
-    buf: [u8; 1 << 10],
-    n: usize,
+    buf: Vec<u8>,
+    max: usize,
+}
+
+impl Message {
+    fn new(dst: SocketAddr) -> Self {
+        Self::with_max_size(dst, MAX_MESSAGE_SIZE)
+    }
+
+    fn with_max_size(dst: SocketAddr, max: usize) -> Self {
+        Self {
+            dst,
+            buf: Vec::new(),
+            max,
+        }
+    }
+
+    /// Wrap an already-received payload, e.g. to hand it off to a peer's
+    /// channel. `dst` here is the peer the bytes came from.
+    fn from_bytes(dst: SocketAddr, buf: Vec<u8>) -> Self {
+        Self {
+            dst,
+            max: MAX_MESSAGE_SIZE,
+            buf,
+        }
+    }
+
+    /// Replace the payload with an already-encoded buffer, e.g. a
+    /// `dns::Message::encode()` result, enforcing the same size bound
+    /// `write_str` enforces for the incremental `fmt::Write` path.
+    fn set_buf(&mut self, buf: Vec<u8>) -> Result<()> {
+        if buf.len() > self.max {
+            return Err(anyhow!("message of {} bytes exceeds max size {}", buf.len(), self.max));
+        }
+        self.buf = buf;
+        Ok(())
+    }
 }
 
 impl Write for Message {
     fn write_str(&mut self, s: &str) -> std::fmt::Result {
-        let Message {
-            dst: _,
-            ref mut buf,
-            ref mut n,
-        } = self;
-        for (i, c) in s.bytes().enumerate() {
-            buf[*n + i] = c;
+        if self.buf.len() + s.len() > self.max {
+            return Err(std::fmt::Error);
         }
-        *n += s.len();
+        self.buf.extend_from_slice(s.as_bytes());
         Ok(())
     }
 }
 
-struct UdpService {
+/// A joined multicast group, left cleanly when dropped.
+enum Membership {
+    V4 {
+        socket: Socket,
+        group: Ipv4Addr,
+        iface: Ipv4Addr,
+    },
+    V6 {
+        socket: Socket,
+        group: Ipv6Addr,
+        iface_index: u32,
+    },
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let result = match self {
+            Membership::V4 {
+                socket,
+                group,
+                iface,
+            } => socket.leave_multicast_v4(group, iface),
+            Membership::V6 {
+                socket,
+                group,
+                iface_index,
+            } => socket.leave_multicast_v6(group, *iface_index),
+        };
+        if let Err(e) = result {
+            log::warn!("failed to leave multicast group: {e}");
+        }
+    }
+}
+
+/// Look up the OS interface index for an interface name, as required by
+/// `join_multicast_v6`.
+fn if_nametoindex(name: &str) -> Result<u32> {
+    let cname = CString::new(name)?;
+    match unsafe { libc::if_nametoindex(cname.as_ptr()) } {
+        0 => Err(anyhow!("unknown network interface {name}")),
+        index => Ok(index),
+    }
+}
+
+/// An IPv4 or IPv6 CIDR block, e.g. `192.168.1.0/24` or `fd00::/8`.
+struct Cidr {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("invalid CIDR {s:?}: expected ADDR/PREFIX"))?;
+        let addr: IpAddr = addr.parse()?;
+        let prefix_len: u32 = prefix_len.parse()?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(anyhow!(
+                "invalid CIDR {s:?}: prefix length {prefix_len} exceeds {max_len}"
+            ));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A single bound, multicast-joined socket for one selected interface.
+struct BoundSocket {
     sock: UdpSocket,
+    iface_name: String,
+    _membership: Membership,
+}
+
+fn bind_and_join(ip: IpAddr, iface_name: &str, port: u16) -> Result<(Socket, Membership)> {
+    match ip {
+        IpAddr::V4(v4) => {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_address(true)?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+            socket.join_multicast_v4(&MDNS_MULTICAST_V4, &v4)?;
+            socket.set_multicast_ttl_v4(255)?;
+            let membership = Membership::V4 {
+                socket: socket.try_clone()?,
+                group: MDNS_MULTICAST_V4,
+                iface: v4,
+            };
+            Ok((socket, membership))
+        }
+        IpAddr::V6(_v6) => {
+            let iface_index = if_nametoindex(iface_name).unwrap_or(0);
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_address(true)?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+            socket.join_multicast_v6(&MDNS_MULTICAST_V6, iface_index)?;
+            socket.set_multicast_hops_v6(255)?;
+            let membership = Membership::V6 {
+                socket: socket.try_clone()?,
+                group: MDNS_MULTICAST_V6,
+                iface_index,
+            };
+            Ok((socket, membership))
+        }
+    }
+}
+
+/// Select every usable interface matching the configured `--iface-cidr` /
+/// `--iface-name` filters (or every usable interface, if neither is set).
+/// Returns an error describing every interface seen and why it was
+/// rejected when nothing matches.
+fn select_interfaces(config: &Config) -> Result<Vec<if_addrs::Interface>> {
+    let cidrs = config
+        .iface_cidrs
+        .iter()
+        .map(|s| Cidr::parse(s))
+        .collect::<Result<Vec<_>>>()?;
+
+    let all_interfaces = if_addrs::get_if_addrs()?;
+    let interfaces: Vec<_> = all_interfaces
+        .iter()
+        .filter(|iface| !iface.is_loopback() && !iface.is_link_local())
+        .cloned()
+        .collect();
+
+    let name_ok = |iface: &if_addrs::Interface| {
+        config.iface_names.is_empty() || config.iface_names.iter().any(|n| n == &iface.name)
+    };
+    let cidr_ok =
+        |iface: &if_addrs::Interface| cidrs.is_empty() || cidrs.iter().any(|c| c.contains(iface.ip()));
+
+    let selected: Vec<_> = interfaces
+        .iter()
+        .filter(|iface| name_ok(iface) && cidr_ok(iface))
+        .cloned()
+        .collect();
+
+    if selected.is_empty() {
+        if interfaces.is_empty() {
+            return Err(anyhow!(
+                "no usable network interfaces found (all were loopback or link-local)"
+            ));
+        }
+        let seen = all_interfaces
+            .iter()
+            .map(|iface| {
+                let reason = if iface.is_loopback() {
+                    "loopback"
+                } else if iface.is_link_local() {
+                    "link-local"
+                } else {
+                    match (name_ok(iface), cidr_ok(iface)) {
+                        (false, _) => "name doesn't match any --iface-name",
+                        (_, false) => "address isn't in any --iface-cidr",
+                        (true, true) => unreachable!("would have been selected"),
+                    }
+                };
+                format!("  {} ({}): {reason}", iface.name, iface.ip())
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        return Err(anyhow!(
+            "no network interface matched the configured filters; interfaces seen:\n{seen}"
+        ));
+    }
+
+    Ok(selected)
+}
+
+struct UdpService {
+    sockets: Arc<Vec<BoundSocket>>,
     rx: Receiver<Message>,
+    demux: Arc<smol::lock::Mutex<mux::Demux>>,
 }
 
 impl UdpService {
-    async fn new(config: &Config) -> Result<(Self, Sender<Message>)> {
-        // select the address to bind to
-        let addr = if_addrs::get_if_addrs()?
-            .into_iter()
-            .filter(|iface| !iface.is_loopback() && !iface.is_link_local() && iface.ip().is_ipv4())
-            .map(|iface| iface.ip())
-            .filter(|ip| match ip {
-                IpAddr::V4(v4) => v4.octets()[0] == 10,
-                IpAddr::V6(_v6) => false,
-            })
-            .next()
-            .ok_or(anyhow!("Failed to select network interface"))?;
+    async fn new(
+        config: &Config,
+    ) -> Result<(
+        Self,
+        Sender<Message>,
+        Receiver<(SocketAddr, Receiver<Message>)>,
+    )> {
+        let mut sockets = Vec::new();
+        for iface in select_interfaces(config)? {
+            let (socket, membership) = match bind_and_join(iface.ip(), &iface.name, config.port) {
+                Ok(result) => result,
+                Err(e) => {
+                    log::warn!("skipping interface {}: {e}", iface.name);
+                    continue;
+                }
+            };
+            socket.set_nonblocking(true)?;
+            let sock = UdpSocket::try_from(std::net::UdpSocket::from(socket))?;
+            info!(
+                "UdpSocket for {} at local addr {:?}",
+                iface.name,
+                sock.local_addr()
+            );
+            sockets.push(BoundSocket {
+                sock,
+                iface_name: iface.name,
+                _membership: membership,
+            });
+        }
+        if sockets.is_empty() {
+            return Err(anyhow!("no interface could be bound/joined"));
+        }
 
-        let sock = UdpSocket::bind((addr, config.port)).await?;
-        info!("UdpSocket at local addr {:?}", sock.local_addr());
         let (tx, rx) = bounded(10);
-        Ok((Self { sock, rx }, tx))
+        let (demux, new_peers) = mux::Demux::new(PEER_IDLE_TIMEOUT);
+        Ok((
+            Self {
+                sockets: Arc::new(sockets),
+                rx,
+                demux: Arc::new(smol::lock::Mutex::new(demux)),
+            },
+            tx,
+            new_peers,
+        ))
     }
 
     async fn run(self) -> Result<()> {
-        let Self { sock, rx } = self;
+        let Self {
+            sockets,
+            rx,
+            demux,
+        } = self;
 
-        info!("LISTENING on {:?}", sock.local_addr());
-        smol::future::try_zip(
-            async {
+        // Each interface gets its own receive loop, running in the
+        // background; inbound datagrams are routed to per-peer channels
+        // through the shared demux.
+        for i in 0..sockets.len() {
+            let sockets = Arc::clone(&sockets);
+            let demux = Arc::clone(&demux);
+            smol::spawn(async move {
+                let bound = &sockets[i];
                 loop {
-                    let Message { dst, buf, n } = rx.recv().await?;
-                    let s = String::from_utf8_lossy(&buf[0..n]);
-                    info!("SEND message to {} \"{}\"", dst, s);
-                    sock.send_to(&buf, dst).await?;
-                }
-                #[allow(unreachable_code)]
-                Ok(())
-            },
-            async {
-                // Receive a single datagram message.
-                // If `buf` is too small to hold the entire message, it will be cut off.
-                loop {
-                    info!("Listening for messages");
-                    let mut buf = vec![0u8; 1 << 10];
-                    let (n, addr) = sock.recv_from(&mut buf).await?;
-                    let s = String::from_utf8_lossy(&buf[0..n]);
-                    info!("RECV \"{}\" FROM {:}", s, addr);
+                    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+                    let (n, addr) = match bound.sock.recv_from(&mut buf).await {
+                        Ok(result) => result,
+                        Err(e) => {
+                            log::warn!("recv error on {}: {e}", bound.iface_name);
+                            break;
+                        }
+                    };
+                    match dns::Message::parse(&buf[0..n]) {
+                        Ok(msg) => info!(
+                            "RECV DNS message on {} FROM {addr}: {msg:?}",
+                            bound.iface_name
+                        ),
+                        Err(e) => {
+                            let s = String::from_utf8_lossy(&buf[0..n]);
+                            info!(
+                                "RECV \"{}\" FROM {} on {} (not a DNS message: {})",
+                                s, addr, bound.iface_name, e
+                            );
+                        }
+                    }
+                    let sender = {
+                        let mut demux = demux.lock().await;
+                        demux.sender_for(addr).await
+                    };
+                    match sender {
+                        Ok(sender) => {
+                            let send_failed = sender
+                                .send(Message::from_bytes(addr, buf[0..n].to_vec()))
+                                .await
+                                .is_err();
+                            demux.lock().await.touch(addr, send_failed);
+                        }
+                        Err(e) => {
+                            log::warn!("failed to get a channel for {addr}: {e}");
+                        }
+                    }
                 }
-                #[allow(unreachable_code)]
-                Ok::<_, anyhow::Error>(())
-            },
-        )
-        .await?;
-        Ok(())
+            })
+            .detach();
+        }
+
+        loop {
+            let Message { dst, buf, .. } = rx.recv().await?;
+            let Some(bound) = sockets
+                .iter()
+                .find(|bound| bound.sock.local_addr().is_ok_and(|a| a.is_ipv4() == dst.is_ipv4()))
+            else {
+                log::warn!("no bound socket matches the address family of {dst}; dropping message");
+                continue;
+            };
+            info!("SEND message to {} \"{}\"", dst, String::from_utf8_lossy(&buf));
+            bound.sock.send_to(&buf, dst).await?;
+        }
     }
 }
 
 struct DiscoveryService {
     config: Config,
     service_addr: SocketAddr,
+    peers: Arc<Mutex<HashSet<SocketAddr>>>,
 }
 
 impl DiscoveryService {
-    fn new(config: &Config, service_addr: SocketAddr) -> Self {
+    fn new(config: &Config, service_addr: SocketAddr, peers: Arc<Mutex<HashSet<SocketAddr>>>) -> Self {
         Self {
             config: config.clone(),
             service_addr,
+            peers,
         }
     }
 
@@ -170,18 +518,26 @@ impl DiscoveryService {
                     for ip in info.get_addresses_v4().into_iter() {
                         info!("ServiceResolved");
 
-                        let mut msg = Message {
-                            dst: SocketAddr::from((*ip, info.get_port())),
-                            buf: [0; 1024],
-                            n: 0,
+                        let addr = SocketAddr::from((*ip, info.get_port()));
+                        self.peers.lock().unwrap().insert(addr);
+
+                        let dns_msg = dns::Message {
+                            flags: dns::Flags {
+                                response: true,
+                                recursion_desired: false,
+                            },
+                            answers: vec![dns::Record {
+                                name: service_name.clone(),
+                                rtype: dns::RecordType::Ptr,
+                                rclass: dns::CLASS_IN,
+                                ttl: 120,
+                                rdata: info.get_fullname().as_bytes().to_vec(),
+                            }],
+                            ..Default::default()
                         };
 
-                        write!(
-                            &mut msg,
-                            "MESSAGE {} Resolved {} END",
-                            config.instance_name,
-                            info.get_fullname()
-                        )?;
+                        let mut msg = Message::new(addr);
+                        msg.set_buf(dns_msg.encode()?)?;
                         info!("ServiceResolved: sending message");
                         tx.send(msg).await?;
                     }
@@ -192,6 +548,62 @@ impl DiscoveryService {
         }
         Ok(())
     }
+
+    /// Browse `config.service_name` for up to `timeout`, print every
+    /// distinct resolved instance, then return without registering
+    /// ourselves or starting the persistent send loop.
+    async fn run_once(self, timeout: Duration) -> Result<()> {
+        info!("STARTING ONE-SHOT DISCOVERY");
+        let config = &self.config;
+        let service_name = format!("{}.local.", config.service_name);
+
+        let service = mdns_sd::ServiceDaemon::new()?;
+        let receiver = service.browse(&service_name)?;
+
+        let mut seen = HashSet::new();
+        let mut instances = Vec::new();
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let event = smol::future::or(
+                async { Some(receiver.recv_async().await) },
+                async {
+                    futures_timer::Delay::new(remaining).await;
+                    None
+                },
+            )
+            .await;
+            match event {
+                None => break,         // timed out
+                Some(Err(_)) => break, // browse channel closed
+                Some(Ok(mdns_sd::ServiceEvent::ServiceResolved(info))) => {
+                    let mut addrs: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+                    addrs.sort();
+                    if seen.insert((info.get_fullname().to_string(), addrs)) {
+                        instances.push(info);
+                    }
+                }
+                Some(Ok(_)) => {}
+            }
+        }
+
+        println!("found {} instance(s) of {service_name}", instances.len());
+        for info in &instances {
+            println!("{} port={}", info.get_fullname(), info.get_port());
+            for ip in info.get_addresses() {
+                println!("  addr={ip}");
+            }
+            for prop in info.get_properties().iter() {
+                println!("  {}={}", prop.key(), prop.val_str());
+            }
+        }
+
+        Ok(())
+    }
 }
 
 fn main() -> Result<()> {
@@ -212,13 +624,66 @@ fn main() -> Result<()> {
             );
         }
 
+        if config.once {
+            let discovery_service = DiscoveryService::new(
+                &config,
+                SocketAddr::from(([0, 0, 0, 0], 0)),
+                Arc::new(Mutex::new(HashSet::new())),
+            );
+            return discovery_service
+                .run_once(Duration::from_millis(config.timeout_ms))
+                .await;
+        }
+
         info!("Spinning up UDP listener");
-        let (udp_service, tx) = UdpService::new(&config).await?;
+        let (udp_service, tx, new_peers) = UdpService::new(&config).await?;
+
+        // Shared across every service that learns about peers (mDNS
+        // discovery, the UDP mux's new-peer announcements, and the beacon
+        // rendezvous fallback), so they all see the same address book.
+        let peers: Arc<Mutex<HashSet<SocketAddr>>> = Arc::new(Mutex::new(HashSet::new()));
+
+        {
+            let peers = Arc::clone(&peers);
+            smol::spawn(async move {
+                while let Ok((addr, peer_rx)) = new_peers.recv().await {
+                    info!("New peer conversation with {addr}");
+                    peers.lock().unwrap().insert(addr);
+                    smol::spawn(async move {
+                        while let Ok(msg) = peer_rx.recv().await {
+                            info!("PEER {} sent {} bytes", addr, msg.buf.len());
+                        }
+                    })
+                    .detach();
+                }
+            })
+            .detach();
+        }
 
         info!("Spinning up mDNS discovery");
-        let discovery_service = DiscoveryService::new(&config, udp_service.sock.local_addr()?);
-
-        smol::future::try_zip(udp_service.run(), discovery_service.run(tx)).await?;
+        let service_addr = udp_service
+            .sockets
+            .first()
+            .ok_or_else(|| anyhow!("UdpService has no bound sockets"))?
+            .sock
+            .local_addr()?;
+        let discovery_service = DiscoveryService::new(&config, service_addr, Arc::clone(&peers));
+
+        let beacon_service = beacon::BeaconService::new(&config, Arc::clone(&peers));
+
+        match beacon_service {
+            Some(beacon_service) => {
+                info!("Spinning up beacon rendezvous");
+                smol::future::try_zip(
+                    smol::future::try_zip(udp_service.run(), discovery_service.run(tx.clone())),
+                    beacon_service.run(tx),
+                )
+                .await?;
+            }
+            None => {
+                smol::future::try_zip(udp_service.run(), discovery_service.run(tx)).await?;
+            }
+        }
 
         Ok(())
     })
@@ -0,0 +1,94 @@
+//! Demultiplex the single shared UDP socket into one channel per peer.
+//!
+//! `UdpService::run` used to just log every inbound datagram. `Demux` turns
+//! that one-way firehose into a many-peer messaging fabric: each distinct
+//! remote `SocketAddr` gets its own `Receiver<Message>`, handed out the
+//! first time that peer is heard from via the `new_peers` channel, and
+//! every following datagram from that peer is routed to the same receiver.
+
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
+use anyhow::Result;
+use log::info;
+use smol::channel::{bounded, unbounded, Receiver, Sender};
+
+use crate::Message;
+
+/// Channel capacity for a single peer's inbound message stream.
+const PEER_CHANNEL_CAPACITY: usize = 32;
+
+struct Peer {
+    tx: Sender<Message>,
+    last_seen: Instant,
+}
+
+/// Routes inbound datagrams to per-peer channels, creating and announcing a
+/// channel the first time a peer is heard from, and forgetting peers that
+/// have gone quiet for longer than `idle_timeout`.
+pub struct Demux {
+    peers: HashMap<SocketAddr, Peer>,
+    new_peers: Sender<(SocketAddr, Receiver<Message>)>,
+    idle_timeout: std::time::Duration,
+}
+
+impl Demux {
+    pub fn new(idle_timeout: std::time::Duration) -> (Self, Receiver<(SocketAddr, Receiver<Message>)>) {
+        let (new_peers, new_peers_rx) = unbounded();
+        (
+            Self {
+                peers: HashMap::new(),
+                new_peers,
+                idle_timeout,
+            },
+            new_peers_rx,
+        )
+    }
+
+    /// Get (creating and announcing if necessary) the channel for `addr`,
+    /// without sending anything on it. Split out from the actual send so
+    /// callers can drop the shared `Demux` lock before doing a potentially
+    /// blocking per-peer send: a single slow or not-yet-scheduled peer must
+    /// not stall routing for every other peer and interface.
+    pub async fn sender_for(&mut self, addr: SocketAddr) -> Result<Sender<Message>> {
+        self.collect_idle_peers();
+
+        if let Some(peer) = self.peers.get(&addr) {
+            return Ok(peer.tx.clone());
+        }
+
+        let (tx, rx) = bounded(PEER_CHANNEL_CAPACITY);
+        self.peers.insert(
+            addr,
+            Peer {
+                tx: tx.clone(),
+                last_seen: Instant::now(),
+            },
+        );
+        info!("MUX new peer {addr}");
+        self.new_peers.send((addr, rx)).await?;
+        Ok(tx)
+    }
+
+    /// Record that `addr` was just heard from, and forget it if its channel
+    /// turned out to be closed (the peer's consumer dropped its receiver).
+    pub fn touch(&mut self, addr: SocketAddr, send_failed: bool) {
+        if send_failed {
+            self.peers.remove(&addr);
+            return;
+        }
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.last_seen = Instant::now();
+        }
+    }
+
+    fn collect_idle_peers(&mut self) {
+        let idle_timeout = self.idle_timeout;
+        self.peers.retain(|addr, peer| {
+            let alive = peer.last_seen.elapsed() < idle_timeout;
+            if !alive {
+                info!("MUX dropping idle peer {addr}");
+            }
+            alive
+        });
+    }
+}